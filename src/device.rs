@@ -0,0 +1,167 @@
+// Detects keyboard devices under /dev/input by probing each character
+// device's capabilities with the EVIOCGBIT ioctl, as described in
+// /linux/Documentation/input/input.txt. This replaces shelling out to
+// `grep` against /proc/bus/input/devices, which relies on a hard-coded
+// EV capability mask that doesn't hold for every keyboard.
+
+use ioctl;
+use input::{EV_KEY, KEY_A, KEY_SPACE, KEY_Z};
+
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+
+use libc::{c_int, c_ulong};
+
+// Number of bytes needed to hold a bitmask covering EV_MAX (0x1f) and
+// KEY_MAX (0x2ff) codes, respectively.
+const EV_BITS_LEN: usize = 4;
+const KEY_BITS_LEN: usize = 96;
+
+const EVIOCGNAME_LEN: usize = 256;
+
+// EVIOCGBIT(ev, len) from <linux/input.h>: read the bitmask of supported
+// codes for event type `ev` into a buffer of `len` bytes.
+fn eviocgbit(ev: u16, len: usize) -> c_ulong {
+    ioctl::ior('E', 0x20 + ev as u32, len)
+}
+
+// EVIOCGNAME(len): read the device's human-readable name into a buffer of
+// `len` bytes.
+fn eviocgname(len: usize) -> c_ulong {
+    ioctl::ior('E', 0x06, len)
+}
+
+// EVIOCGRAB: take (value 1) or release (value 0) exclusive access to a
+// device, so its events stop reaching X/Wayland while we're logging them.
+// Despite being an _IOW request, the kernel reads the argument as a bare
+// integer rather than a pointer to one.
+fn eviocgrab() -> c_ulong {
+    ioctl::iow('E', 0x90, mem::size_of::<c_int>())
+}
+
+fn bit_is_set(bitmask: &[u8], bit: u16) -> bool {
+    let byte = bitmask[bit as usize / 8];
+    (byte >> (bit % 8)) & 1 == 1
+}
+
+// Returns true if the given device file supports EV_KEY events and reports a
+// representative set of alphabetic keys, which filters out EV_KEY-capable
+// devices that aren't real keyboards (e.g. volume or power buttons).
+fn is_keyboard_device(file: &File) -> bool {
+    let fd = file.as_raw_fd();
+
+    let mut ev_bits = [0u8; EV_BITS_LEN];
+    let res = unsafe {
+        libc::ioctl(
+            fd,
+            eviocgbit(0, ev_bits.len()),
+            ev_bits.as_mut_ptr() as *mut c_int,
+        )
+    };
+    if res < 0 || !bit_is_set(&ev_bits, EV_KEY) {
+        return false;
+    }
+
+    let mut key_bits = [0u8; KEY_BITS_LEN];
+    let res = unsafe {
+        libc::ioctl(
+            fd,
+            eviocgbit(EV_KEY, key_bits.len()),
+            key_bits.as_mut_ptr() as *mut c_int,
+        )
+    };
+    if res < 0 {
+        return false;
+    }
+
+    bit_is_set(&key_bits, KEY_A) && bit_is_set(&key_bits, KEY_Z) && bit_is_set(&key_bits, KEY_SPACE)
+}
+
+// Reads the device's EVIOCGNAME string, falling back to "<unknown>" if the
+// ioctl fails or the name isn't valid UTF-8.
+fn device_name(file: &File) -> String {
+    let mut buf = [0u8; EVIOCGNAME_LEN];
+    let res = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            eviocgname(buf.len()),
+            buf.as_mut_ptr() as *mut c_int,
+        )
+    };
+    if res < 0 {
+        return "<unknown>".to_owned();
+    }
+    let cstr = unsafe { CStr::from_ptr(buf.as_ptr() as *const _) };
+    cstr.to_str().unwrap_or("<unknown>").to_owned()
+}
+
+fn event_device_paths() -> Vec<String> {
+    let mut paths: Vec<String> = fs::read_dir("/dev/input")
+        .unwrap_or_else(|e| panic!("{}", e))
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with("event"))
+        .map(|name| format!("/dev/input/{}", name))
+        .collect();
+    paths.sort();
+    paths
+}
+
+// Enumerates /dev/input/event* and returns the paths of those that look like
+// keyboards, based on their ioctl-reported capabilities.
+pub fn get_keyboard_device_filenames() -> Vec<String> {
+    event_device_paths()
+        .into_iter()
+        .filter(|path| {
+            File::open(path)
+                .ok()
+                .map_or(false, |file| is_keyboard_device(&file))
+        })
+        .collect()
+}
+
+// Opens `path` and returns the file if it looks like a keyboard, or `None`
+// otherwise. Used to vet a device node that showed up after startup, e.g.
+// one reported by a hotplug watch on /dev/input.
+pub fn open_if_keyboard(path: &str) -> Option<File> {
+    File::open(path).ok().and_then(|file| {
+        if is_keyboard_device(&file) {
+            Some(file)
+        } else {
+            None
+        }
+    })
+}
+
+// Takes (`grab = true`) or releases (`grab = false`) exclusive ownership of
+// `file` via EVIOCGRAB. While grabbed, the device's events are delivered
+// only to us, not to X/Wayland.
+//
+// The value must be cast directly to a pointer (`as *mut c_int`), not
+// passed by reference: the kernel only null-checks this argument rather
+// than reading an int through it, so a `&value` pointer -- always
+// non-null, whatever `value` holds -- would be read as "grab" even when
+// releasing.
+pub fn set_grab(file: &File, grab: bool) -> io::Result<()> {
+    let value = if grab { 1 } else { 0 } as *mut c_int;
+    let res = unsafe { libc::ioctl(file.as_raw_fd(), eviocgrab(), value) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+// Prints every /dev/input/event* node alongside its EVIOCGNAME string, for
+// the `--list-devices` flag.
+pub fn list_devices() {
+    for path in event_device_paths() {
+        match File::open(&path) {
+            Ok(file) => println!("{}\t{}", path, device_name(&file)),
+            Err(e) => println!("{}\t<error: {}>", path, e),
+        }
+    }
+}
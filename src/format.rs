@@ -0,0 +1,83 @@
+// Renders a single key event as a line of output, in one of the encodings
+// selectable via `--format`.
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    // The original `+KEY`/`-KEY` style.
+    Compact,
+    // `<tv_sec>.<tv_usec> <event> <KEY>`.
+    Timestamped,
+    // One JSON object per line.
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "compact" => Some(Format::Compact),
+            "timestamped" => Some(Format::Timestamped),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+// Mirrors the `+ - ± ?` cases in the press/release state machine: a plain
+// press, a plain release, a key pressed and released with nothing in
+// between, and a release with no matching press.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Press,
+    Release,
+    PressAndRelease,
+    OrphanRelease,
+}
+
+impl Kind {
+    fn sign(&self) -> char {
+        match *self {
+            Kind::Press => '+',
+            Kind::Release => '-',
+            Kind::PressAndRelease => '±',
+            Kind::OrphanRelease => '?',
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Press => "press",
+            Kind::Release => "release",
+            Kind::PressAndRelease => "press-and-release",
+            Kind::OrphanRelease => "orphan-release",
+        }
+    }
+}
+
+// Renders one line describing a key event: its kernel timestamp, code, text
+// and kind, according to `format`.
+pub fn render(format: Format, tv_sec: isize, tv_usec: isize, code: u16, key: &str, kind: Kind) -> String {
+    match format {
+        Format::Compact => format!("{}{}", kind.sign(), key),
+        Format::Timestamped => format!("{}.{} {} {}", tv_sec, tv_usec, kind.name(), key),
+        Format::Json => format!(
+            "{{\"tv_sec\":{},\"tv_usec\":{},\"code\":{},\"key\":{},\"event\":{}}}",
+            tv_sec, tv_usec, code, json_string(key), json_string(kind.name())
+        ),
+    }
+}
+
+// Minimal JSON string escaping. Key names and event kinds are always drawn
+// from the small fixed ASCII sets in `KEY_NAMES` (input.rs) and `Kind::name`,
+// so a full JSON serializer isn't warranted here.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
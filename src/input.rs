@@ -1,17 +1,23 @@
 // Constants, structs, and arrays derived from /linux/include/linux/input.h
 
-const MAX_KEYS: u16 = 127;
+pub const MAX_KEYS: u16 = 127;
 
-const EV_KEY: u16 = 1;
+pub const EV_KEY: u16 = 1;
 
 const KEY_RELEASE: i32 = 0;
 const KEY_PRESS: i32 = 1;
 
+// A handful of alphabetic/whitespace key codes used to distinguish a real
+// keyboard from other EV_KEY-capable devices (e.g. volume or power buttons).
+pub const KEY_A: u16 = 30;
+pub const KEY_Z: u16 = 44;
+pub const KEY_SPACE: u16 = 57;
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct InputEvent {
-    tv_sec: isize, // from timeval struct
-    tv_usec: isize, // from timeval struct
+    pub tv_sec: isize, // from timeval struct
+    pub tv_usec: isize, // from timeval struct
     pub type_: u16,
     pub code: u16,
     pub value: i32
@@ -73,3 +79,11 @@ pub fn is_key_release(value: i32) -> bool {
     value == KEY_RELEASE
 }
 
+// The inverse of `get_key_text`: looks a key code up by its `KEY_NAMES`
+// entry, e.g. for resolving the key names used in a remap config. Returns
+// the first matching code, so it isn't meaningful for the repeated `<UK>`
+// entries.
+pub fn get_key_code(name: &str) -> Option<u16> {
+    KEY_NAMES.iter().position(|&n| n == name).map(|code| code as u16)
+}
+
@@ -0,0 +1,42 @@
+// Generic helpers for building Linux ioctl request numbers, mirroring the
+// _IO/_IOR/_IOW macros from <asm-generic/ioctl.h>. Shared by `device` (the
+// evdev ioctls) and `remap` (the uinput ioctls).
+
+use libc::c_ulong;
+
+const NRBITS: u32 = 8;
+const TYPEBITS: u32 = 8;
+const SIZEBITS: u32 = 14;
+
+const NRSHIFT: u32 = 0;
+const TYPESHIFT: u32 = NRSHIFT + NRBITS;
+const SIZESHIFT: u32 = TYPESHIFT + TYPEBITS;
+const DIRSHIFT: u32 = SIZESHIFT + SIZEBITS;
+
+const NONE: u32 = 0;
+const WRITE: u32 = 1;
+const READ: u32 = 2;
+
+fn build(dir: u32, ty: char, nr: u32, size: u32) -> c_ulong {
+    ((dir << DIRSHIFT) | ((ty as u32) << TYPESHIFT) | (nr << NRSHIFT) | (size << SIZESHIFT))
+        as c_ulong
+}
+
+// _IO(ty, nr): a argument-less request, e.g. UI_DEV_CREATE.
+pub fn io(ty: char, nr: u32) -> c_ulong {
+    build(NONE, ty, nr, 0)
+}
+
+// _IOR(ty, nr, size): a request that reads `size` bytes back from the
+// kernel, e.g. EVIOCGBIT.
+pub fn ior(ty: char, nr: u32, size: usize) -> c_ulong {
+    build(READ, ty, nr, size as u32)
+}
+
+// _IOW(ty, nr, size): a request that writes `size` bytes to the kernel,
+// e.g. UI_SET_EVBIT. Some such requests (EVIOCGRAB, UI_SET_EVBIT) actually
+// pass their argument as a bare integer rather than a pointer to one, per
+// the usual evdev/uinput convention.
+pub fn iow(ty: char, nr: u32, size: usize) -> c_ulong {
+    build(WRITE, ty, nr, size as u32)
+}
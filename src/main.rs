@@ -5,13 +5,25 @@ extern crate libc;
 #[macro_use]
 extern crate log;
 
+mod device;
+mod format;
 mod input;
+mod ioctl;
+mod remap;
+mod watch;
 
+use device::get_keyboard_device_filenames;
+use format::{Format, Kind};
 use input::{is_key_event, is_key_press, is_key_release, get_key_text, InputEvent};
+use remap::{Keymap, VirtualDevice};
+use watch::{Change, Watch};
 
-use std::process::{exit, Command};
+use std::process::exit;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{env, mem};
 
 use getopts::Options;
@@ -20,13 +32,112 @@ const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 #[derive(Debug)]
 struct Config {
-    device_file: String,
-    log_file: String
+    device_files: Vec<String>,
+    log_file: String,
+    grab: bool,
+    format: Format,
+    remap: Option<String>
 }
 
 impl Config {
-    fn new(device_file: String, log_file: String) -> Self {
-        Config { device_file: device_file, log_file: log_file }
+    fn new(device_files: Vec<String>, log_file: String, grab: bool, format: Format, remap: Option<String>) -> Self {
+        Config { device_files: device_files, log_file: log_file, grab: grab, format: format, remap: remap }
+    }
+}
+
+// Set to false by the SIGINT/SIGTERM handler so the main loop can unwind and
+// release any grabbed devices instead of leaving them stuck on a crash.
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
+extern "C" fn request_shutdown(_signum: libc::c_int) {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() {
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as libc::sighandler_t);
+    }
+}
+
+// How often the main loop wakes from `epoll_wait` to check whether a signal
+// has asked us to shut down.
+const POLL_TIMEOUT_MS: i32 = 1000;
+
+// Per-device bookkeeping for the press/release state machine. Each open
+// device gets its own, keyed by its fd, so events from one keyboard never
+// get attributed to another.
+struct DeviceState {
+    path: String,
+    file: File,
+
+    // Keys that are currently pressed on this device.
+    holding_down: Vec<u16>,
+
+    // This is used to help us track when a key is simply pressed and released
+    // without any other key events inbetween, so we can log it as a single
+    // 'press and release' event, instead of two seperate 'press' then 'release'
+    // events. I.e. if a key is released, and that key is the last entry in the
+    // `holding_down` vector, and the last event was a press, then this is the
+    // case.
+    key_was_just_pressed: bool
+}
+
+impl DeviceState {
+    fn new(path: String, file: File) -> Self {
+        DeviceState { path: path, file: file, holding_down: Vec::new(), key_was_just_pressed: false }
+    }
+}
+
+// Maximum number of epoll events drained per `epoll_wait` call. Unrelated to
+// how many devices are registered; epoll only ever fills in what's ready.
+const MAX_EPOLL_EVENTS: usize = 16;
+
+fn register_fd(epoll_fd: RawFd, fd: RawFd) {
+    let mut event = libc::epoll_event { events: libc::EPOLLIN as u32, u64: fd as u64 };
+    let res = unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) };
+    if res < 0 {
+        panic!("Failed to register fd {} with epoll", fd);
+    }
+}
+
+fn deregister_fd(epoll_fd: RawFd, fd: RawFd) {
+    // The event pointer is ignored for EPOLL_CTL_DEL, but older kernels
+    // (pre-2.6.9) required a non-null one, so pass a zeroed one to be safe.
+    let mut event: libc::epoll_event = unsafe { mem::zeroed() };
+    unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_DEL, fd, &mut event) };
+}
+
+// Opens `path` if it's a keyboard, registers it with `epoll_fd` and adds it
+// to `devices`. If `grab` is set, takes exclusive ownership of the device.
+fn attach_device(epoll_fd: RawFd, devices: &mut HashMap<RawFd, DeviceState>, path: String, grab: bool) {
+    if let Some(file) = device::open_if_keyboard(&path) {
+        if grab {
+            if let Err(e) = device::set_grab(&file, true) {
+                warn!("Failed to grab {}: {}", path, e);
+            }
+        }
+        let fd = file.as_raw_fd();
+        register_fd(epoll_fd, fd);
+        devices.insert(fd, DeviceState::new(path.clone(), file));
+        debug!("Attached device {}", path);
+    }
+}
+
+// Drops the device at `path` from `devices`, deregistering it from epoll.
+// No-op if `path` isn't a currently tracked device.
+fn detach_device_by_path(epoll_fd: RawFd, devices: &mut HashMap<RawFd, DeviceState>, path: &str) {
+    let fd = devices.iter().find(|&(_, state)| state.path == path).map(|(&fd, _)| fd);
+    if let Some(fd) = fd {
+        detach_device(epoll_fd, devices, fd);
+    }
+}
+
+// Drops the device at `fd` from `devices`, deregistering it from epoll.
+fn detach_device(epoll_fd: RawFd, devices: &mut HashMap<RawFd, DeviceState>, fd: RawFd) {
+    if let Some(state) = devices.remove(&fd) {
+        deregister_fd(epoll_fd, fd);
+        debug!("Detached device {}", state.path);
     }
 }
 
@@ -38,82 +149,162 @@ fn main() {
     let config = parse_args();
     debug!("Config: {:?}", config);
 
+    install_signal_handlers();
+
     let mut log_file = OpenOptions::new().create(true).write(true).append(true).open(config.log_file)
         .unwrap_or_else(|e| panic!("{}", e));
-    let mut device_file = File::open(&config.device_file).unwrap_or_else(|e| panic!("{}", e));
+
+    let epoll_fd = unsafe { libc::epoll_create1(0) };
+    if epoll_fd < 0 {
+        panic!("Failed to create epoll instance");
+    }
+
+    let mut devices: HashMap<RawFd, DeviceState> = HashMap::new();
+    for path in &config.device_files {
+        let file = File::open(path).unwrap_or_else(|e| panic!("{}", e));
+        if config.grab {
+            if let Err(e) = device::set_grab(&file, true) {
+                warn!("Failed to grab {}: {}", path, e);
+            }
+        }
+        let fd = file.as_raw_fd();
+        register_fd(epoll_fd, fd);
+        devices.insert(fd, DeviceState::new(path.clone(), file));
+    }
+
+    // When remapping, translated copies of the real devices' key events are
+    // replayed through this virtual device; the keymap says how to translate
+    // them. Logging (below) still runs on the untranslated event stream.
+    // The virtual device is created before the /dev/input watch below, so
+    // its own event node is not picked up as a hotplugged keyboard.
+    let keymap = config.remap.as_ref().map(|path| Keymap::load(path));
+    let mut virtual_device = config.remap.as_ref().map(|_| VirtualDevice::create());
+
+    let mut watch = Watch::new();
+    register_fd(epoll_fd, watch.as_raw_fd());
 
     // TODO: use the sizeof function (not available yet) instead of hard-coding 24.
     let mut buf: [u8; 24] = unsafe { mem::zeroed() };
 
-	// Keys that are currently pressed.
-    let mut holding_down = Vec::new();
+    let mut epoll_events: Vec<libc::epoll_event> = vec![unsafe { mem::zeroed() }; MAX_EPOLL_EVENTS];
 
-    // This is used to help us track when a key is simply pressed and released
-    // without any other key events inbetween, so we can log it as a single
-    // 'press and release' event, instead of two seperate 'press' then 'release'
-    // events. I.e. if a key is released, and that key is the last entry in the
-    // `holding_down` vector, and the last event was a press, then this is the
-    // case.
-    let mut key_was_just_pressed = false;
-
-    loop {
-        let num_bytes = device_file.read(&mut buf).unwrap_or_else(|e| panic!("{}", e));
-        if num_bytes != mem::size_of::<InputEvent>() {
-            panic!("Error while reading from device file");
+    while RUNNING.load(Ordering::SeqCst) {
+        let num_fds = unsafe {
+            libc::epoll_wait(epoll_fd, epoll_events.as_mut_ptr(), epoll_events.len() as i32, POLL_TIMEOUT_MS)
+        };
+        if num_fds < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINTR) {
+                // A signal (most likely our own shutdown handler) interrupted
+                // the wait; loop around to re-check `RUNNING`.
+                continue;
+            }
+            panic!("epoll_wait failed: {}", err);
         }
-        let event: InputEvent = unsafe { mem::transmute(buf) };
-        if is_key_event(event.type_) {
-            if is_key_press(event.value) {
-                if key_was_just_pressed {
-                    // Log the press event for the previously pressed key.
-                    print_key(*holding_down.last().unwrap(), '+', &mut log_file);
+
+        for epoll_event in &epoll_events[..num_fds as usize] {
+            let fd = epoll_event.u64 as RawFd;
+
+            if fd == watch.as_raw_fd() {
+                for change in watch.read_changes() {
+                    match change {
+                        Change::Created(path) => attach_device(epoll_fd, &mut devices, path, config.grab),
+                        Change::Removed(path) => detach_device_by_path(epoll_fd, &mut devices, &path),
+                    }
                 }
-                key_was_just_pressed = true;
-                holding_down.push(event.code);
-            } else if is_key_release(event.value) {
-                if let Some(position) = holding_down.iter()
-                    .position(|x| *x == event.code) {
-                        if position + 1 == holding_down.len() {
-                            // Of all the keys we're holding, the one that is
-                            // being released now is the one that was pressed
-                            // last.
-                            if key_was_just_pressed {
-                                // No other key has been pressed and released
-                                // in the meantime, so we log the event as a
-                                // single 'press and release' event.
-                                print_key(event.code, '±', &mut log_file);
-                            } else {
-                                // Another key has been pressed and released
-                                // in the meantime.
-                                print_key(event.code, '-', &mut log_file);
-                            }
-                            holding_down.pop();
-                        } else {
-                            if key_was_just_pressed {
-                                // Another key was pressed after this one, but the
-                                // newest key has not yet been logged. So lets
-                                // log the press event for that key right before
-                                // logging the release event for this key.
-                                print_key(*holding_down.last().unwrap(), '+', &mut log_file);
-                            }
-                            print_key(event.code, '-', &mut log_file);
-                            holding_down.remove(position);
-                        }
-                        key_was_just_pressed = false;
+                continue;
+            }
+
+            let state = match devices.get_mut(&fd) {
+                Some(state) => state,
+                // The device may have just been detached; nothing to do.
+                None => continue,
+            };
+
+            match state.file.read(&mut buf) {
+                Ok(n) if n == mem::size_of::<InputEvent>() => {}
+                _ => {
+                    // The device most likely disappeared (e.g. unplugged).
+                    // Drop it instead of crashing the whole logger.
+                    detach_device(epoll_fd, &mut devices, fd);
+                    continue;
+                }
+            }
+            let event: InputEvent = unsafe { mem::transmute(buf) };
+
+            if is_key_event(event.type_) {
+                if let (&Some(ref keymap), &mut Some(ref mut virtual_device)) = (&keymap, &mut virtual_device) {
+                    virtual_device.emit(&event, keymap.translate(event.code));
+                }
+            }
+
+            handle_event(event, state, &mut log_file, config.format);
+        }
+    }
+
+    if config.grab {
+        for state in devices.values() {
+            let _ = device::set_grab(&state.file, false);
+        }
+    }
+    let _ = log_file.flush();
+}
+
+fn handle_event(event: InputEvent, state: &mut DeviceState, log_file: &mut File, format: Format) {
+    if !is_key_event(event.type_) {
+        return;
+    }
+
+    if is_key_press(event.value) {
+        if state.key_was_just_pressed {
+            // Log the press event for the previously pressed key.
+            print_key(&event, *state.holding_down.last().unwrap(), Kind::Press, format, log_file);
+        }
+        state.key_was_just_pressed = true;
+        state.holding_down.push(event.code);
+    } else if is_key_release(event.value) {
+        if let Some(position) = state.holding_down.iter()
+            .position(|x| *x == event.code) {
+                if position + 1 == state.holding_down.len() {
+                    // Of all the keys we're holding, the one that is
+                    // being released now is the one that was pressed
+                    // last.
+                    if state.key_was_just_pressed {
+                        // No other key has been pressed and released
+                        // in the meantime, so we log the event as a
+                        // single 'press and release' event.
+                        print_key(&event, event.code, Kind::PressAndRelease, format, log_file);
                     } else {
-                        // Did we release a key that was never registered as
-                        // pressed? I don't think this will happen much, but I
-                        // suppose we might as well log it.
-                        print_key(event.code, '?', &mut log_file);
+                        // Another key has been pressed and released
+                        // in the meantime.
+                        print_key(&event, event.code, Kind::Release, format, log_file);
+                    }
+                    state.holding_down.pop();
+                } else {
+                    if state.key_was_just_pressed {
+                        // Another key was pressed after this one, but the
+                        // newest key has not yet been logged. So lets
+                        // log the press event for that key right before
+                        // logging the release event for this key.
+                        print_key(&event, *state.holding_down.last().unwrap(), Kind::Press, format, log_file);
                     }
+                    print_key(&event, event.code, Kind::Release, format, log_file);
+                    state.holding_down.remove(position);
+                }
+                state.key_was_just_pressed = false;
+            } else {
+                // Did we release a key that was never registered as
+                // pressed? I don't think this will happen much, but I
+                // suppose we might as well log it.
+                print_key(&event, event.code, Kind::OrphanRelease, format, log_file);
             }
-        }
     }
 }
 
-fn print_key(code: u16, sign: char, file: &mut File) {
+fn print_key(event: &InputEvent, code: u16, kind: Kind, format: Format, file: &mut File) {
     let text = get_key_text(code);
-    write!(file, "{}{}\n", sign, text).unwrap_or_else(|e| panic!("{}", e));
+    let line = format::render(format, event.tv_sec, event.tv_usec, code, text, kind);
+    writeln!(file, "{}", line).unwrap_or_else(|e| panic!("{}", e));
 }
 
 fn root_check() {
@@ -134,8 +325,12 @@ fn parse_args() -> Config {
     let mut opts = Options::new();
     opts.optflag("h", "help", "prints this help message");
     opts.optflag("v", "version", "prints the version");
-    opts.optopt("d", "device", "specify the device file", "DEVICE");
+    opts.optmulti("d", "device", "specify a device file (may be repeated)", "DEVICE");
     opts.optopt("f", "file", "specify the file to log to", "FILE");
+    opts.optflag("", "list-devices", "list detected input devices and exit");
+    opts.optflag("g", "grab", "exclusively grab devices, hiding their events from X/Wayland");
+    opts.optopt("", "format", "output format: compact (default), timestamped, or json", "FORMAT");
+    opts.optopt("", "remap", "remap keys according to the [keymap] table in CONFIG, via a uinput virtual device", "CONFIG");
 
     let matches = opts.parse(&args[1..]).unwrap_or_else(|e| panic!("{}", e));
     if matches.opt_present("h") {
@@ -148,43 +343,35 @@ fn parse_args() -> Config {
         exit(0);
     }
 
-    let device_file = matches.opt_str("d").unwrap_or_else(|| get_default_device());
+    if matches.opt_present("list-devices") {
+        device::list_devices();
+        exit(0);
+    }
+
+    let device_files = matches.opt_strs("device");
+    let device_files = if device_files.is_empty() {
+        get_default_devices()
+    } else {
+        device_files
+    };
     let log_file = matches.opt_str("f").unwrap_or("keys.log".to_owned());
+    let remap = matches.opt_str("remap");
+    // Remapping requires exclusive ownership of the real device, otherwise
+    // both the original and remapped keys would reach X/Wayland.
+    let grab = matches.opt_present("grab") || remap.is_some();
+    let format = matches.opt_str("format").map_or(Format::Compact, |name| {
+        Format::from_name(&name).unwrap_or_else(|| panic!("Unknown format: {}", name))
+    });
 
-    Config::new(device_file, log_file)
+    Config::new(device_files, log_file, grab, format, remap)
 }
 
-fn get_default_device() -> String {
-    let mut filenames = get_keyboard_device_filenames();
+fn get_default_devices() -> Vec<String> {
+    let filenames = get_keyboard_device_filenames();
     debug!("Detected devices: {:?}", filenames);
 
-    if filenames.len() == 1 {
-        filenames.swap_remove(0)
-    } else {
-        panic!("The following keyboard devices were detected: {:?}. Please select one using \
-                the `-d` flag", filenames);
-    }
-}
-
-// Detects and returns the name of the keyboard device file. This function uses
-// the fact that all device information is shown in /proc/bus/input/devices and
-// the keyboard device file should always have an EV of 120013
-fn get_keyboard_device_filenames() -> Vec<String> {
-    let mut command_str = "grep -E 'Handlers|EV' /proc/bus/input/devices".to_string();
-    command_str.push_str("| grep -B1 120013");
-    command_str.push_str("| grep -Eo event[0-9]+");
-
-    let res = Command::new("sh").arg("-c").arg(command_str).output().unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
-    let res_str = std::str::from_utf8(&res.stdout).unwrap();
-
-    let mut filenames = Vec::new();
-    for file in res_str.trim().split('\n') {
-        let mut filename = "/dev/input/".to_string();
-        filename.push_str(file);
-        filenames.push(filename);
+    if filenames.is_empty() {
+        panic!("No keyboard devices were detected. Please select one using the `-d` flag");
     }
     filenames
 }
-
@@ -0,0 +1,217 @@
+// Optional remapping mode (`--remap <config.toml>`): translates incoming
+// key events according to a `[keymap]` table and replays them through a
+// uinput virtual device, so the logger doubles as a key remapper. Passive
+// logging (see `handle_event` in main.rs) still runs on the pre-translation
+// stream; this module only concerns itself with the translated copy.
+
+use ioctl;
+use input::{self, InputEvent};
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::slice;
+
+use libc::{c_int, c_ulong};
+
+const EV_SYN: u16 = 0;
+const SYN_REPORT: u16 = 0;
+
+const UINPUT_MAX_NAME_SIZE: usize = 80;
+const ABS_CNT: usize = 64;
+
+const UINPUT_DEVICE_NAME: &'static str = "keylogger-remap";
+
+#[repr(C)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16
+}
+
+// Mirrors struct uinput_user_dev from <linux/uinput.h>, the legacy (but
+// ABI-stable) way to describe and create a uinput device.
+#[repr(C)]
+struct UinputUserDev {
+    name: [u8; UINPUT_MAX_NAME_SIZE],
+    id: InputId,
+    ff_effects_max: u32,
+    absmax: [i32; ABS_CNT],
+    absmin: [i32; ABS_CNT],
+    absfuzz: [i32; ABS_CNT],
+    absflat: [i32; ABS_CNT]
+}
+
+fn ui_set_evbit() -> c_ulong {
+    ioctl::iow('U', 100, mem::size_of::<c_int>())
+}
+
+fn ui_set_keybit() -> c_ulong {
+    ioctl::iow('U', 101, mem::size_of::<c_int>())
+}
+
+fn ui_dev_create() -> c_ulong {
+    ioctl::io('U', 1)
+}
+
+fn ui_dev_destroy() -> c_ulong {
+    ioctl::io('U', 2)
+}
+
+// A `from = to` keymap, parsed from the `[keymap]` table of a remap config.
+// Names on both sides are resolved with `input::get_key_code`, i.e. they're
+// the same strings `input::KEY_NAMES` uses (`"a"`, `"<CapsLock>"`, ...).
+pub struct Keymap {
+    codes: HashMap<u16, u16>
+}
+
+impl Keymap {
+    // Parses the minimal TOML subset this config format needs: a single
+    // `[keymap]` table of `"from" = "to"` entries.
+    pub fn load(path: &str) -> Keymap {
+        let mut file = File::open(path).unwrap_or_else(|e| panic!("{}", e));
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).unwrap_or_else(|e| panic!("{}", e));
+
+        let mut codes = HashMap::new();
+        let mut in_keymap = false;
+        let mut saw_keymap_section = false;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                in_keymap = line.trim_matches(|c| c == '[' || c == ']') == "keymap";
+                saw_keymap_section = saw_keymap_section || in_keymap;
+                continue;
+            }
+            if !in_keymap {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let from = parts.next().unwrap_or("").trim().trim_matches('"');
+            let to = parts.next().unwrap_or("").trim().trim_matches('"');
+
+            match (input::get_key_code(from), input::get_key_code(to)) {
+                (Some(from_code), Some(to_code)) => {
+                    codes.insert(from_code, to_code);
+                }
+                _ => warn!("Ignoring unrecognised keymap entry: {} = {}", from, to),
+            }
+        }
+
+        if !saw_keymap_section {
+            warn!("No [keymap] section found in {}; no keys will be remapped", path);
+        }
+
+        Keymap { codes: codes }
+    }
+
+    // Returns the code `code` remaps to, or `code` itself if it isn't in
+    // the keymap (unmapped keys pass through unchanged).
+    pub fn translate(&self, code: u16) -> u16 {
+        *self.codes.get(&code).unwrap_or(&code)
+    }
+}
+
+// A uinput virtual device that remapped key events are replayed through.
+pub struct VirtualDevice {
+    file: File
+}
+
+impl VirtualDevice {
+    // Creates a uinput device capable of emitting any key in
+    // `input::KEY_NAMES`, so both remapped and passed-through keys can
+    // always be written to it.
+    pub fn create() -> VirtualDevice {
+        let mut file = OpenOptions::new().write(true).open("/dev/uinput")
+            .unwrap_or_else(|e| panic!("{}", e));
+        let fd = file.as_raw_fd();
+
+        set_evbit(fd, input::EV_KEY).unwrap_or_else(|e| panic!("{}", e));
+        for code in 0..input::MAX_KEYS {
+            set_keybit(fd, code).unwrap_or_else(|e| panic!("{}", e));
+        }
+
+        let mut dev: UinputUserDev = unsafe { mem::zeroed() };
+        let name = CString::new(UINPUT_DEVICE_NAME).unwrap();
+        let name_bytes = name.as_bytes_with_nul();
+        dev.name[..name_bytes.len()].copy_from_slice(name_bytes);
+        dev.id.bustype = 0x06; // BUS_VIRTUAL
+        dev.id.vendor = 0x0001;
+        dev.id.product = 0x0001;
+        dev.id.version = 1;
+
+        let dev_bytes = unsafe {
+            slice::from_raw_parts(
+                &dev as *const UinputUserDev as *const u8,
+                mem::size_of::<UinputUserDev>(),
+            )
+        };
+        file.write_all(dev_bytes).unwrap_or_else(|e| panic!("{}", e));
+
+        let res = unsafe { libc::ioctl(fd, ui_dev_create()) };
+        if res < 0 {
+            panic!("Failed to create uinput device: {}", io::Error::last_os_error());
+        }
+
+        VirtualDevice { file: file }
+    }
+
+    // Writes a translated key event to the virtual device, followed by an
+    // EV_SYN/SYN_REPORT so it's delivered as a single input report.
+    pub fn emit(&mut self, source: &InputEvent, code: u16) {
+        self.write_event(InputEvent {
+            tv_sec: source.tv_sec,
+            tv_usec: source.tv_usec,
+            type_: input::EV_KEY,
+            code: code,
+            value: source.value,
+        });
+        self.write_event(InputEvent {
+            tv_sec: source.tv_sec,
+            tv_usec: source.tv_usec,
+            type_: EV_SYN,
+            code: SYN_REPORT,
+            value: 0,
+        });
+    }
+
+    fn write_event(&mut self, event: InputEvent) {
+        let buf: [u8; 24] = unsafe { mem::transmute(event) };
+        self.file.write_all(&buf).unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        unsafe { libc::ioctl(self.file.as_raw_fd(), ui_dev_destroy()) };
+    }
+}
+
+fn set_evbit(fd: RawFd, ev: u16) -> io::Result<()> {
+    ioctl_with_value(fd, ui_set_evbit(), ev as c_int)
+}
+
+fn set_keybit(fd: RawFd, code: u16) -> io::Result<()> {
+    ioctl_with_value(fd, ui_set_keybit(), code as c_int)
+}
+
+// UI_SET_EVBIT/UI_SET_KEYBIT are declared as _IOW requests, but like
+// EVIOCGRAB the kernel reads the argument as a bare integer rather than a
+// pointer to one.
+fn ioctl_with_value(fd: RawFd, request: c_ulong, value: c_int) -> io::Result<()> {
+    let res = unsafe { libc::ioctl(fd, request, value as *mut c_int) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
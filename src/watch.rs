@@ -0,0 +1,90 @@
+// Watches /dev/input for devices being plugged in or unplugged, using
+// inotify, so the epoll-based main loop can attach or detach keyboards at
+// runtime instead of only enumerating them once at startup.
+
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use libc::inotify_event;
+
+const DEV_INPUT: &'static [u8] = b"/dev/input\0";
+
+#[derive(Debug)]
+pub enum Change {
+    Created(String),
+    Removed(String),
+}
+
+pub struct Watch {
+    file: File,
+}
+
+impl Watch {
+    pub fn new() -> Self {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK) };
+        if fd < 0 {
+            panic!("Failed to initialize inotify");
+        }
+
+        let wd = unsafe {
+            libc::inotify_add_watch(
+                fd,
+                DEV_INPUT.as_ptr() as *const i8,
+                libc::IN_CREATE | libc::IN_DELETE,
+            )
+        };
+        if wd < 0 {
+            panic!("Failed to watch /dev/input");
+        }
+
+        Watch { file: unsafe { File::from_raw_fd(fd) } }
+    }
+
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    // Reads and parses whatever inotify events are currently pending,
+    // ignoring entries that aren't event* nodes.
+    pub fn read_changes(&mut self) -> Vec<Change> {
+        let mut buf = [0u8; 4096];
+        let num_bytes = match self.file.read(&mut buf) {
+            Ok(n) => n,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut changes = Vec::new();
+        let mut offset = 0;
+        while offset + mem::size_of::<inotify_event>() <= num_bytes {
+            let event = unsafe { &*(buf[offset..].as_ptr() as *const inotify_event) };
+            let name_start = offset + mem::size_of::<inotify_event>();
+            let name_end = name_start + event.len as usize;
+
+            // IN_Q_OVERFLOW and IN_IGNORED events carry no name (`len == 0`);
+            // there's nothing to read in that case, and the bytes after the
+            // event header aren't part of it. The name field itself is
+            // NUL-padded out to `event.len`, so bound the search for the
+            // terminator to that slice rather than scanning an unbounded
+            // pointer for one.
+            if event.len != 0 {
+                let name_bytes = &buf[name_start..name_end];
+                let nul_pos = name_bytes.iter().position(|&b| b == 0).unwrap_or(name_bytes.len());
+                let name = String::from_utf8_lossy(&name_bytes[..nul_pos]).into_owned();
+
+                if name.starts_with("event") {
+                    let path = format!("/dev/input/{}", name);
+                    if event.mask & libc::IN_CREATE != 0 {
+                        changes.push(Change::Created(path));
+                    } else if event.mask & libc::IN_DELETE != 0 {
+                        changes.push(Change::Removed(path));
+                    }
+                }
+            }
+
+            offset = name_end;
+        }
+        changes
+    }
+}